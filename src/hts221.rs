@@ -0,0 +1,120 @@
+//! Minimal register-level driver for the STMicro HTS221 temperature/humidity sensor.
+//!
+//! Unlike the LIS3DH we don't pull in a full driver crate for this one: the datasheet's
+//! two-point calibration procedure is simple enough to read and convert directly over the
+//! shared I2C bus.
+
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+
+const ADDRESS: u8 = 0x5F;
+
+const WHO_AM_I: u8 = 0x0F;
+const WHO_AM_I_VALUE: u8 = 0xBC;
+
+const CTRL_REG1: u8 = 0x20;
+const HUMIDITY_OUT_L: u8 = 0x28;
+
+const CALIBRATION_START: u8 = 0x30;
+const CALIBRATION_LEN: usize = 16;
+
+/// Auto-increment flag that must be OR'd into a register address for multi-byte reads.
+const AUTO_INCREMENT: u8 = 0x80;
+
+/// The two-point linear calibration coefficients baked into the sensor at the factory, read
+/// once at startup.
+struct Calibration {
+    h0_rh: f32,
+    h1_rh: f32,
+    h0_t0_out: i16,
+    h1_t0_out: i16,
+    t0_degc: f32,
+    t1_degc: f32,
+    t0_out: i16,
+    t1_out: i16,
+}
+
+/// A single temperature/humidity reading, converted via the stored [`Calibration`].
+#[derive(Debug, Clone, Copy)]
+pub struct Reading {
+    pub temperature_c: f32,
+    pub humidity_percent: f32,
+}
+
+pub struct Hts221<I2C> {
+    i2c: I2C,
+    calibration: Calibration,
+}
+
+impl<I2C, E> Hts221<I2C>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+{
+    /// Powers on the sensor, reads its factory calibration registers, and returns a driver ready
+    /// for [`Hts221::read`].
+    pub fn new(mut i2c: I2C) -> Result<Self, E> {
+        let mut who_am_i = [0u8];
+        i2c.write_read(ADDRESS, &[WHO_AM_I], &mut who_am_i)?;
+        debug_assert_eq!(who_am_i[0], WHO_AM_I_VALUE);
+
+        // Power up, 1 Hz output data rate, block data update so a read always sees a consistent
+        // humidity/temperature pair.
+        i2c.write(ADDRESS, &[CTRL_REG1, 0b1000_0101])?;
+
+        let calibration = Self::read_calibration(&mut i2c)?;
+
+        Ok(Self { i2c, calibration })
+    }
+
+    fn read_calibration(i2c: &mut I2C) -> Result<Calibration, E> {
+        let mut raw = [0u8; CALIBRATION_LEN];
+        i2c.write_read(ADDRESS, &[CALIBRATION_START | AUTO_INCREMENT], &mut raw)?;
+
+        let h0_rh = raw[0] as f32 / 2.0;
+        let h1_rh = raw[1] as f32 / 2.0;
+
+        // T0/T1_degC are 10-bit values: 8 LSBs each in their own register, 2 MSBs packed
+        // together in a single byte.
+        let t1t0_msb = raw[5];
+        let t0_degc_x8 = raw[2] as u16 | (((t1t0_msb & 0b0000_0011) as u16) << 8);
+        let t1_degc_x8 = raw[3] as u16 | ((((t1t0_msb >> 2) & 0b0000_0011) as u16) << 8);
+
+        let h0_t0_out = i16::from_le_bytes([raw[6], raw[7]]);
+        let h1_t0_out = i16::from_le_bytes([raw[10], raw[11]]);
+        let t0_out = i16::from_le_bytes([raw[12], raw[13]]);
+        let t1_out = i16::from_le_bytes([raw[14], raw[15]]);
+
+        Ok(Calibration {
+            h0_rh,
+            h1_rh,
+            h0_t0_out,
+            h1_t0_out,
+            t0_degc: t0_degc_x8 as f32 / 8.0,
+            t1_degc: t1_degc_x8 as f32 / 8.0,
+            t0_out,
+            t1_out,
+        })
+    }
+
+    /// Reads the current humidity and temperature, converted to physical units via the stored
+    /// calibration points.
+    pub fn read(&mut self) -> Result<Reading, E> {
+        let mut raw = [0u8; 4];
+        self.i2c
+            .write_read(ADDRESS, &[HUMIDITY_OUT_L | AUTO_INCREMENT], &mut raw)?;
+
+        let h_out = i16::from_le_bytes([raw[0], raw[1]]);
+        let t_out = i16::from_le_bytes([raw[2], raw[3]]);
+
+        let c = &self.calibration;
+        let humidity_percent = c.h0_rh
+            + (c.h1_rh - c.h0_rh) * (h_out - c.h0_t0_out) as f32
+                / (c.h1_t0_out - c.h0_t0_out) as f32;
+        let temperature_c = c.t0_degc
+            + (c.t1_degc - c.t0_degc) * (t_out - c.t0_out) as f32 / (c.t1_out - c.t0_out) as f32;
+
+        Ok(Reading {
+            temperature_c,
+            humidity_percent,
+        })
+    }
+}
@@ -11,32 +11,17 @@
 #![feature(alloc_error_handler)]
 
 //extern crate panic_semihosting;
-extern crate panic_halt;
-
-use nb::block;
+use defmt_rtt as _;
+use panic_probe as _;
 
 #[macro_use]
 extern crate alloc;
 use alloc_cortex_m::CortexMHeap;
 use core::alloc::Layout;
 
-use accelerometer::Accelerometer;
-use lis3dh::{Lis3dh, SlaveAddr};
-use micromath::F32Ext;
-
-use cortex_m_rt::entry;
-use embedded_graphics::{
-    fonts::Text, pixelcolor::BinaryColor, prelude::*, style::TextStyleBuilder,
-};
-use profont::ProFont18Point;
-use shared_bus;
-use ssd1306::{mode::GraphicsMode, Builder, I2CDIBuilder};
-use stm32f1xx_hal::{
-    i2c::{BlockingI2c, DutyCycle, Mode},
-    pac,
-    prelude::*,
-    timer::Timer,
-};
+#[cfg(feature = "hts221")]
+mod hts221;
+mod setup;
 
 #[global_allocator]
 static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
@@ -44,138 +29,381 @@ static ALLOCATOR: CortexMHeap = CortexMHeap::empty();
 const MAX_LENGTH: usize = 5;
 const DEC_FIGS: usize = 2;
 
-const LOOPS_PER_SEC: u32 = 100;
-
-#[entry]
-fn main() -> ! {
-    // Initialize the allocator BEFORE you use it
-    let start = cortex_m_rt::heap_start() as usize;
-    let size = 1024; // in bytes
-    unsafe { ALLOCATOR.init(start, size) }
-
-    // Get access to the core peripherals from the cortex-m crate
-    let cp = cortex_m::Peripherals::take().unwrap();
-    // Get access to the device specific peripherals from the peripheral access crate
-    let dp = pac::Peripherals::take().unwrap();
-
-    // Take ownership over the raw flash and rcc devices and convert them into the corresponding
-    // HAL structs
-    let mut flash = dp.FLASH.constrain();
-    let mut rcc = dp.RCC.constrain();
-
-    // Freeze the configuration of all the clocks in the system and store the frozen frequencies in
-    // `clocks`
-    let clocks = rcc.cfgr.freeze(&mut flash.acr);
-
-    // Configure the syst timer to trigger an update every second
-    let mut timer = Timer::syst(cp.SYST, &clocks).start_count_down(LOOPS_PER_SEC.hz());
-
-    // I2C config
-    let mut afio = dp.AFIO.constrain(&mut rcc.apb2);
-    let mut gpiob = dp.GPIOB.split(&mut rcc.apb2);
-
-    let scl1 = gpiob.pb8.into_alternate_open_drain(&mut gpiob.crh);
-    let sda1 = gpiob.pb9.into_alternate_open_drain(&mut gpiob.crh);
-
-    let i2c1 = BlockingI2c::i2c1(
-        dp.I2C1,
-        (scl1, sda1),
-        &mut afio.mapr,
-        Mode::Fast {
-            frequency: 400_000.hz(),
-            duty_cycle: DutyCycle::Ratio2to1,
-        },
-        clocks,
-        &mut rcc.apb1,
-        1000,
-        10,
-        1000,
-        1000,
-    );
-
-    // Share the I2C bus across all attached devices
-    // Otherwise the first device would take ownership of the bus
-    // and no other devices could be attached
-    let bus = shared_bus::BusManagerSimple::new(i2c1);
-
-    let interface = I2CDIBuilder::new().init(bus.acquire_i2c());
-
-    let mut disp: GraphicsMode<_, _> = Builder::new().connect(interface).into();
-    disp.init().unwrap();
-
-    disp.clear();
-    disp.flush().unwrap();
-
-    // 18 Point font is 12 x 22 pixels
-    let text_style = TextStyleBuilder::new(ProFont18Point)
-        .text_color(BinaryColor::On)
-        .build();
-
-    let mut lis3dh = Lis3dh::new(bus.acquire_i2c(), SlaveAddr::Default).unwrap();
-    lis3dh.set_range(lis3dh::Range::G8).unwrap();
-
-    let mut peak = 0.0;
-
-    loop {
-        let accel = lis3dh.accel_norm().unwrap();
-        let (x, y, z) = (accel.x, accel.y, accel.z);
-        let abs_mag = ((x * x + y * y + z * z).sqrt() - 1.0).abs();
-        if abs_mag > peak {
-            peak = abs_mag;
+/// Sensor task runs at this rate; the display task runs much slower (see [`DISPLAY_HZ`])
+/// so that redrawing the OLED never throttles how often we sample the accelerometer.
+const SENSOR_HZ: u32 = 100;
+const DISPLAY_HZ: u32 = 2;
+
+/// How many display-task ticks each page stays on screen before cycling to the next one.
+///
+/// Only meaningful with the `hts221` feature: without it there's only one page, so nothing
+/// cycles.
+#[cfg(feature = "hts221")]
+const TICKS_PER_PAGE: u32 = DISPLAY_HZ * 3;
+
+/// The 128x64 OLED can't show acceleration and environment readings at 18-point font at the
+/// same time, so the display cycles between pages instead. Gated behind the `hts221` feature
+/// since the environment page has nothing to show without that sensor.
+#[cfg(feature = "hts221")]
+#[derive(Clone, Copy)]
+enum Page {
+    Acceleration,
+    Environment,
+}
+
+#[cfg(feature = "hts221")]
+impl Page {
+    fn next(self) -> Self {
+        match self {
+            Page::Acceleration => Page::Environment,
+            Page::Environment => Page::Acceleration,
+        }
+    }
+}
+
+/// Number of raw magnitude samples averaged together for the displayed "Cur" value.
+const SMOOTHING_WINDOW: usize = 20;
+
+/// Inbound USB serial byte that resets the stored peak and the moving-average window.
+const RESET_COMMAND: u8 = b'r';
+
+/// Log the current/peak magnitude once every this many sensor samples, so RTT isn't flooded at
+/// [`SENSOR_HZ`].
+const LOG_EVERY_N_SAMPLES: u32 = SENSOR_HZ;
+
+/// I2C1 fast-mode bus frequency shared by the SSD1306, LIS3DH and HTS221.
+const I2C_FREQUENCY_HZ: u32 = 400_000;
+
+// Timeouts passed to `BlockingI2c::i2c1`. `BlockingI2c` takes these directly as microseconds
+// (not bus/pclk1 cycles), so there's no clock-derived quantity to compute here — a duration is
+// a duration regardless of the configured pclk1.
+const I2C_START_TIMEOUT_US: u32 = 1_000;
+const I2C_START_RETRIES: u8 = 10;
+const I2C_ADDR_TIMEOUT_US: u32 = 1_000;
+const I2C_DATA_TIMEOUT_US: u32 = 1_000;
+
+/// Fixed-size ring buffer of the last [`SMOOTHING_WINDOW`] magnitude samples plus a running sum,
+/// so the displayed current value doesn't jitter frame-to-frame.
+struct MovingAverage {
+    samples: [f32; SMOOTHING_WINDOW],
+    next: usize,
+    count: usize,
+    sum: f32,
+}
+
+impl MovingAverage {
+    const fn new() -> Self {
+        Self {
+            samples: [0.0; SMOOTHING_WINDOW],
+            next: 0,
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    /// Records `sample` and returns the updated average.
+    fn push(&mut self, sample: f32) -> f32 {
+        self.sum -= self.samples[self.next];
+        self.samples[self.next] = sample;
+        self.sum += sample;
+
+        self.next = (self.next + 1) % SMOOTHING_WINDOW;
+        if self.count < SMOOTHING_WINDOW {
+            self.count += 1;
         }
 
-        // let x_text = format!("X: {:>width$.dec$}", x, width = MAX_LENGTH, dec = DEC_FIGS);
-        // let y_text = format!("Y: {:>width$.dec$}", y, width = MAX_LENGTH, dec = DEC_FIGS);
-        // let z_text = format!("Z: {:>width$.dec$}", z, width = MAX_LENGTH, dec = DEC_FIGS);
+        self.sum / self.count as f32
+    }
+
+    /// Discards every recorded sample.
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[rtic::app(device = stm32f1xx_hal::pac, peripherals = true)]
+mod app {
+    use super::{
+        setup::{self, Accel, Display, I2c1Bus},
+        MovingAverage, ALLOCATOR, DEC_FIGS, LOG_EVERY_N_SAMPLES, MAX_LENGTH, RESET_COMMAND,
+    };
+    #[cfg(feature = "hts221")]
+    use super::{hts221::Hts221, setup::SharedI2c1, Page, TICKS_PER_PAGE};
+
+    use accelerometer::Accelerometer;
+    use alloc::string::String;
+    use embedded_graphics::{
+        fonts::Text, pixelcolor::BinaryColor, prelude::*, style::TextStyleBuilder,
+    };
+    use micromath::F32Ext;
+    use profont::ProFont18Point;
+    use shared_bus::{BusManager, NullMutex};
+    use stm32f1xx_hal::{
+        pac::{TIM2, TIM3},
+        timer::CountDownTimer,
+        usb::UsbBusType,
+    };
+    use usb_device::bus::UsbBusAllocator;
+    use usb_device::prelude::*;
+    use usbd_serial::SerialPort;
+
+    /// Latest sampled acceleration: `(x, y, z, smoothed_mag)`.
+    type Reading = (f32, f32, f32, f32);
+
+    #[shared]
+    struct Shared {
+        peak: f32,
+        latest: Reading,
+        reset_requested: bool,
+        usb_dev: UsbDevice<'static, UsbBusType>,
+        serial: SerialPort<'static, UsbBusType>,
+    }
+
+    #[local]
+    struct Local {
+        lis3dh: Accel,
+        #[cfg(feature = "hts221")]
+        hts221: Hts221<SharedI2c1>,
+        disp: Display,
+        sensor_timer: CountDownTimer<TIM2>,
+        display_timer: CountDownTimer<TIM3>,
+        smoother: MovingAverage,
+        log_countdown: u32,
+        #[cfg(feature = "hts221")]
+        page: Page,
+        #[cfg(feature = "hts221")]
+        page_countdown: u32,
+    }
+
+    #[init(local = [
+        bus_manager: Option<BusManager<NullMutex<I2c1Bus>>> = None,
+        usb_bus: Option<UsbBusAllocator<UsbBusType>> = None,
+    ])]
+    fn init(cx: init::Context) -> (Shared, Local, init::Monotonics) {
+        // Initialize the allocator BEFORE you use it
+        let start = cortex_m_rt::heap_start() as usize;
+        let size = 1024; // in bytes
+        unsafe { ALLOCATOR.init(start, size) }
+
+        let app = setup::init(cx.device, cx.local.bus_manager, cx.local.usb_bus);
+
+        (
+            Shared {
+                peak: 0.0,
+                latest: (0.0, 0.0, 0.0, 0.0),
+                reset_requested: false,
+                usb_dev: app.usb_dev,
+                serial: app.serial,
+            },
+            Local {
+                lis3dh: app.lis3dh,
+                #[cfg(feature = "hts221")]
+                hts221: app.hts221,
+                disp: app.disp,
+                sensor_timer: app.sensor_timer,
+                display_timer: app.display_timer,
+                smoother: MovingAverage::new(),
+                log_countdown: LOG_EVERY_N_SAMPLES,
+                #[cfg(feature = "hts221")]
+                page: Page::Acceleration,
+                #[cfg(feature = "hts221")]
+                page_countdown: TICKS_PER_PAGE,
+            },
+            init::Monotonics(),
+        )
+    }
+
+    /// Samples the LIS3DH, updates the shared peak/latest-reading state, and streams a
+    /// `x,y,z,magnitude,smoothed_magnitude,peak` line over USB serial. `peak` tracks
+    /// `smoothed_magnitude`, not `magnitude`, so both are sent explicitly rather than leaving a
+    /// host to assume `peak` bounds the raw `magnitude` column. Runs at [`SENSOR_HZ`],
+    /// independent of how often the display is redrawn.
+    #[task(binds = TIM2, local = [lis3dh, sensor_timer, smoother, log_countdown], shared = [peak, latest, reset_requested, serial])]
+    fn sample(mut cx: sample::Context) {
+        cx.local.sensor_timer.clear_update_interrupt_flag();
+
+        let accel = cx.local.lis3dh.accel_norm().unwrap();
+        let (x, y, z) = (accel.x, accel.y, accel.z);
+        let abs_mag = ((x * x + y * y + z * z).sqrt() - 1.0).abs();
 
-        let cur_text = format!(
-            "Cur: {:>width$.dec$}",
+        let smoother = cx.local.smoother;
+        let (peak, smoothed_mag) =
+            (cx.shared.peak, cx.shared.reset_requested).lock(|peak, reset_requested| {
+                if *reset_requested {
+                    *peak = 0.0;
+                    smoother.reset();
+                    *reset_requested = false;
+                }
+                // Peak tracking runs on the smoothed magnitude too, so a single spurious spike
+                // can't set an artificially high "Max".
+                let smoothed_mag = smoother.push(abs_mag);
+                if smoothed_mag > *peak {
+                    *peak = smoothed_mag;
+                }
+                (*peak, smoothed_mag)
+            });
+
+        cx.shared.latest.lock(|latest| *latest = (x, y, z, smoothed_mag));
+
+        let line = format!(
+            "{:.dec$},{:.dec$},{:.dec$},{:.dec$},{:.dec$},{:.dec$}\r\n",
+            x,
+            y,
+            z,
             abs_mag,
-            width = MAX_LENGTH,
+            smoothed_mag,
+            peak,
             dec = DEC_FIGS
         );
+        cx.shared.serial.lock(|serial| {
+            let _ = serial.write(line.as_bytes());
+        });
+
+        *cx.local.log_countdown -= 1;
+        if *cx.local.log_countdown == 0 {
+            *cx.local.log_countdown = LOG_EVERY_N_SAMPLES;
+            defmt::debug!("cur = {} peak = {}", smoothed_mag, peak);
+        }
+    }
 
-        let peak_text = format!(
-            "Max: {:>width$.dec$}",
-            peak,
-            width = MAX_LENGTH,
-            dec = DEC_FIGS
+    /// Services the USB stack: polls the device/serial pair and looks for an inbound
+    /// [`RESET_COMMAND`] byte that clears the stored peak and smoothing window.
+    #[task(binds = USB_LP_CAN_RX0, shared = [usb_dev, serial, reset_requested])]
+    fn usb_rx(mut cx: usb_rx::Context) {
+        (cx.shared.usb_dev, cx.shared.serial, cx.shared.reset_requested).lock(
+            |usb_dev, serial, reset_requested| {
+                if usb_dev.poll(&mut [serial]) {
+                    let mut buf = [0u8; 8];
+                    if let Ok(count) = serial.read(&mut buf) {
+                        if buf[..count].contains(&RESET_COMMAND) {
+                            *reset_requested = true;
+                        }
+                    }
+                }
+            },
         );
+    }
+
+    /// Formats the acceleration page's "Cur"/"Max" lines.
+    fn acceleration_page_text(abs_mag: f32, peak: f32) -> (String, String) {
+        (
+            format!(
+                "Cur: {:>width$.dec$}",
+                abs_mag,
+                width = MAX_LENGTH,
+                dec = DEC_FIGS
+            ),
+            format!(
+                "Max: {:>width$.dec$}",
+                peak,
+                width = MAX_LENGTH,
+                dec = DEC_FIGS
+            ),
+        )
+    }
+
+    /// Reads the shared state and redraws the OLED. Runs at [`DISPLAY_HZ`], much slower than
+    /// the sensor task, since the display doesn't need to be refreshed on every sample.
+    ///
+    /// With the `hts221` feature enabled, cycles between the acceleration and environment
+    /// pages; without it, there's only ever an acceleration page to draw.
+    #[cfg(feature = "hts221")]
+    #[task(binds = TIM3, local = [disp, display_timer, hts221, page, page_countdown], shared = [peak, latest])]
+    fn render(mut cx: render::Context) {
+        cx.local.display_timer.clear_update_interrupt_flag();
+
+        *cx.local.page_countdown -= 1;
+        if *cx.local.page_countdown == 0 {
+            *cx.local.page_countdown = TICKS_PER_PAGE;
+            *cx.local.page = cx.local.page.next();
+        }
 
+        // 18 Point font is 12 x 22 pixels
+        let text_style = TextStyleBuilder::new(ProFont18Point)
+            .text_color(BinaryColor::On)
+            .build();
+
+        let (top_text, bottom_text) = match cx.local.page {
+            Page::Acceleration => {
+                let abs_mag = cx.shared.latest.lock(|latest| latest.3);
+                let peak = cx.shared.peak.lock(|peak| *peak);
+
+                acceleration_page_text(abs_mag, peak)
+            }
+            Page::Environment => {
+                let reading = cx.local.hts221.read().unwrap();
+
+                (
+                    format!(
+                        "T: {:>width$.dec$}C",
+                        reading.temperature_c,
+                        width = MAX_LENGTH,
+                        dec = DEC_FIGS
+                    ),
+                    format!(
+                        "H: {:>width$.dec$}%",
+                        reading.humidity_percent,
+                        width = MAX_LENGTH,
+                        dec = DEC_FIGS
+                    ),
+                )
+            }
+        };
+
+        let disp = cx.local.disp;
         disp.clear();
 
-        // Text::new(&x_text, Point::zero())
-        //     .into_styled(text_style)
-        //     .draw(&mut disp)
-        //     .unwrap();
+        Text::new(&top_text, Point::zero())
+            .into_styled(text_style)
+            .draw(disp)
+            .unwrap();
+
+        Text::new(&bottom_text, Point::new(0, 23))
+            .into_styled(text_style)
+            .draw(disp)
+            .unwrap();
 
-        // Text::new(&y_text, Point::new(0, 23))
-        //     .into_styled(text_style)
-        //     .draw(&mut disp)
-        //     .unwrap();
+        disp.flush().unwrap();
+    }
 
-        // Text::new(&z_text, Point::new(0, 46))
-        //     .into_styled(text_style)
-        //     .draw(&mut disp)
-        //     .unwrap();
+    /// Reads the shared state and redraws the OLED. Runs at [`DISPLAY_HZ`], much slower than
+    /// the sensor task, since the display doesn't need to be refreshed on every sample.
+    ///
+    /// Without the `hts221` feature there's no environment page to cycle to, so this always
+    /// draws the acceleration page.
+    #[cfg(not(feature = "hts221"))]
+    #[task(binds = TIM3, local = [disp, display_timer], shared = [peak, latest])]
+    fn render(mut cx: render::Context) {
+        cx.local.display_timer.clear_update_interrupt_flag();
+
+        // 18 Point font is 12 x 22 pixels
+        let text_style = TextStyleBuilder::new(ProFont18Point)
+            .text_color(BinaryColor::On)
+            .build();
+
+        let abs_mag = cx.shared.latest.lock(|latest| latest.3);
+        let peak = cx.shared.peak.lock(|peak| *peak);
+        let (top_text, bottom_text) = acceleration_page_text(abs_mag, peak);
+
+        let disp = cx.local.disp;
+        disp.clear();
 
-        Text::new(&cur_text, Point::zero())
+        Text::new(&top_text, Point::zero())
             .into_styled(text_style)
-            .draw(&mut disp)
+            .draw(disp)
             .unwrap();
 
-        Text::new(&peak_text, Point::new(0, 23))
+        Text::new(&bottom_text, Point::new(0, 23))
             .into_styled(text_style)
-            .draw(&mut disp)
+            .draw(disp)
             .unwrap();
 
         disp.flush().unwrap();
-
-        block!(timer.wait()).unwrap();
     }
 }
 
 #[alloc_error_handler]
-fn oom(_: Layout) -> ! {
+fn oom(layout: Layout) -> ! {
+    defmt::error!("out of memory: failed to allocate {} bytes", layout.size());
     loop {}
 }
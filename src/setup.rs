@@ -0,0 +1,239 @@
+//! Peripheral bring-up, extracted out of `init` so the flash/rcc/clocks/I2C/display/sensor
+//! wiring is reusable and testable independent of how the RTIC app assembles its resources.
+//!
+//! `init` only owns the clock tree, the two task timers and the GPIO/AFIO/RCC split; everything hung off the shared
+//! I2C1 bus or USB is brought up by a dedicated `setup_*` helper that borrows `&mut` of whichever
+//! shared pieces (`gpiob`, `afio`, `rcc.apb1`, ...) it needs, so peripherals on the same split
+//! GPIO bank can be mixed and matched freely.
+
+#[cfg(feature = "hts221")]
+use crate::hts221::Hts221;
+use crate::{
+    I2C_ADDR_TIMEOUT_US, I2C_DATA_TIMEOUT_US, I2C_FREQUENCY_HZ, I2C_START_RETRIES,
+    I2C_START_TIMEOUT_US,
+};
+
+use lis3dh::{Lis3dh, SlaveAddr};
+use shared_bus::{BusManager, NullMutex};
+use ssd1306::{mode::GraphicsMode, prelude::*, Builder, I2CDIBuilder};
+use stm32f1xx_hal::{
+    afio,
+    gpio::{
+        gpioa::{self, PA11, PA12},
+        gpiob::{self, PB8, PB9},
+        Alternate, Floating, Input, OpenDrain,
+    },
+    i2c::{BlockingI2c, DutyCycle, Mode},
+    pac,
+    pac::{I2C1, TIM2, TIM3, USB},
+    prelude::*,
+    rcc::{Clocks, APB1},
+    timer::{CountDownTimer, Event, Timer},
+    usb::{Peripheral, UsbBus, UsbBusType},
+};
+use usb_device::{bus::UsbBusAllocator, prelude::*};
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+pub type I2c1Bus = BlockingI2c<I2C1, (PB8<Alternate<OpenDrain>>, PB9<Alternate<OpenDrain>>)>;
+pub type SharedI2c1 = shared_bus::I2cProxy<'static, NullMutex<I2c1Bus>>;
+pub type Accel = Lis3dh<SharedI2c1>;
+pub type Display = GraphicsMode<I2CInterface<SharedI2c1>>;
+
+/// Every initialized peripheral `init` hands off to the RTIC shared/local resources.
+pub struct App {
+    pub lis3dh: Accel,
+    #[cfg(feature = "hts221")]
+    pub hts221: Hts221<SharedI2c1>,
+    pub disp: Display,
+    pub sensor_timer: CountDownTimer<TIM2>,
+    pub display_timer: CountDownTimer<TIM3>,
+    pub usb_dev: UsbDevice<'static, UsbBusType>,
+    pub serial: SerialPort<'static, UsbBusType>,
+}
+
+/// Consumes the device peripherals and brings up the clock tree, the shared I2C1 bus and every
+/// device on it, the sensor/display timer tasks, and the USB CDC serial stack.
+///
+/// `bus_manager` and `usb_bus` are `'static` storage owned by the caller (RTIC's `#[init(local =
+/// [...])]`), since `shared_bus` and `usb-device` both hand out references into the
+/// manager/allocator that must outlive this function.
+pub fn init(
+    dp: pac::Peripherals,
+    bus_manager: &'static mut Option<BusManager<NullMutex<I2c1Bus>>>,
+    usb_bus: &'static mut Option<UsbBusAllocator<UsbBusType>>,
+) -> App {
+    // Take ownership over the raw flash and rcc devices and convert them into the corresponding
+    // HAL structs
+    let mut flash = dp.FLASH.constrain();
+    let mut rcc = dp.RCC.constrain();
+
+    // Drive the core from the external 8 MHz crystal instead of the imprecise internal HSI, at
+    // the blue pill's maximum 72 MHz with APB1 at its 36 MHz limit. `clocks` holds the
+    // frequencies that actually got configured, which are handed to the timer, I2C and USB
+    // setup below instead of being assumed there.
+    let clocks = rcc
+        .cfgr
+        .use_hse(8.mhz())
+        .sysclk(72.mhz())
+        .pclk1(36.mhz())
+        .freeze(&mut flash.acr);
+    defmt::info!(
+        "clocks frozen: sysclk = {} Hz, pclk1 = {} Hz",
+        clocks.sysclk().0,
+        clocks.pclk1().0
+    );
+
+    // Hardware timer tasks: one drives the sensor sample rate, the other the display refresh
+    // rate, so the two are fully decoupled.
+    let mut sensor_timer =
+        Timer::tim2(dp.TIM2, &clocks, &mut rcc.apb1).start_count_down(crate::SENSOR_HZ.hz());
+    sensor_timer.listen(Event::Update);
+
+    let mut display_timer =
+        Timer::tim3(dp.TIM3, &clocks, &mut rcc.apb1).start_count_down(crate::DISPLAY_HZ.hz());
+    display_timer.listen(Event::Update);
+
+    let mut afio = dp.AFIO.constrain(&mut rcc.apb2);
+    let mut gpiob = dp.GPIOB.split(&mut rcc.apb2);
+    let mut gpioa = dp.GPIOA.split(&mut rcc.apb2);
+
+    let i2c1 = setup_i2c1(
+        dp.I2C1,
+        gpiob.pb8,
+        gpiob.pb9,
+        &mut gpiob.crh,
+        &mut afio.mapr,
+        &mut rcc.apb1,
+        clocks,
+    );
+
+    // Share the I2C bus across all attached devices
+    // Otherwise the first device would take ownership of the bus
+    // and no other devices could be attached
+    let bus_manager = bus_manager.insert(shared_bus::BusManagerSimple::new(i2c1));
+
+    let disp = setup_display(bus_manager);
+    let lis3dh = setup_accel(bus_manager);
+    #[cfg(feature = "hts221")]
+    let hts221 = setup_hts221(bus_manager);
+
+    let (usb_dev, serial) = setup_usb(
+        dp.USB,
+        gpioa.pa11,
+        gpioa.pa12,
+        &mut gpioa.crh,
+        clocks,
+        usb_bus,
+    );
+
+    App {
+        lis3dh,
+        #[cfg(feature = "hts221")]
+        hts221,
+        disp,
+        sensor_timer,
+        display_timer,
+        usb_dev,
+        serial,
+    }
+}
+
+/// Configures PB8/PB9 as the I2C1 SCL/SDA pins and brings up the bus in fast mode.
+///
+/// Takes `&mut` of `gpiob`'s CRH register, `afio`'s MAPR and `rcc`'s APB1 rather than owning
+/// them outright, since other peripherals on the same GPIO bank/bus need them too.
+fn setup_i2c1(
+    i2c1: I2C1,
+    scl: PB8<Input<Floating>>,
+    sda: PB9<Input<Floating>>,
+    crh: &mut gpiob::CRH,
+    mapr: &mut afio::MAPR,
+    apb1: &mut APB1,
+    clocks: Clocks,
+) -> I2c1Bus {
+    let scl1 = scl.into_alternate_open_drain(crh);
+    let sda1 = sda.into_alternate_open_drain(crh);
+
+    BlockingI2c::i2c1(
+        i2c1,
+        (scl1, sda1),
+        mapr,
+        Mode::Fast {
+            frequency: I2C_FREQUENCY_HZ.hz(),
+            duty_cycle: DutyCycle::Ratio2to1,
+        },
+        clocks,
+        apb1,
+        I2C_START_TIMEOUT_US,
+        I2C_START_RETRIES,
+        I2C_ADDR_TIMEOUT_US,
+        I2C_DATA_TIMEOUT_US,
+    )
+}
+
+/// Brings up the SSD1306 OLED on the shared I2C1 bus.
+fn setup_display(bus_manager: &'static BusManager<NullMutex<I2c1Bus>>) -> Display {
+    let interface = I2CDIBuilder::new().init(bus_manager.acquire_i2c());
+
+    let mut disp: GraphicsMode<_, _> = Builder::new().connect(interface).into();
+    disp.init().unwrap();
+
+    disp.clear();
+    disp.flush().unwrap();
+    defmt::info!("I2C and display initialized");
+
+    disp
+}
+
+/// Brings up the LIS3DH accelerometer on the shared I2C1 bus.
+fn setup_accel(bus_manager: &'static BusManager<NullMutex<I2c1Bus>>) -> Accel {
+    let mut lis3dh = Lis3dh::new(bus_manager.acquire_i2c(), SlaveAddr::Default).unwrap();
+    lis3dh.set_range(lis3dh::Range::G8).unwrap();
+    defmt::info!("LIS3DH initialized");
+
+    lis3dh
+}
+
+/// Brings up the HTS221 temperature/humidity sensor on the shared I2C1 bus.
+#[cfg(feature = "hts221")]
+fn setup_hts221(bus_manager: &'static BusManager<NullMutex<I2c1Bus>>) -> Hts221<SharedI2c1> {
+    let hts221 = Hts221::new(bus_manager.acquire_i2c()).unwrap();
+    defmt::info!("HTS221 initialized");
+
+    hts221
+}
+
+/// Brings up the USB CDC serial stack used to stream telemetry to a host. Forces a USB
+/// re-enumeration by briefly pulling D+ low, since the blue pill doesn't reset it for us on boot.
+///
+/// Takes `&mut` of `gpioa`'s CRH register rather than owning it outright, since other
+/// peripherals on the same GPIO bank may need it too.
+fn setup_usb(
+    usb: USB,
+    pin_dm: PA11<Input<Floating>>,
+    pin_dp: PA12<Input<Floating>>,
+    crh: &mut gpioa::CRH,
+    clocks: Clocks,
+    usb_bus: &'static mut Option<UsbBusAllocator<UsbBusType>>,
+) -> (UsbDevice<'static, UsbBusType>, SerialPort<'static, UsbBusType>) {
+    let mut usb_dp = pin_dp.into_push_pull_output(crh);
+    usb_dp.set_low();
+    cortex_m::asm::delay(clocks.sysclk().0 / 100);
+
+    let usb = Peripheral {
+        usb,
+        pin_dm,
+        pin_dp: usb_dp.into_floating_input(crh),
+    };
+    let usb_bus = usb_bus.insert(UsbBus::new(usb));
+
+    let serial = SerialPort::new(usb_bus);
+    let usb_dev = UsbDeviceBuilder::new(usb_bus, UsbVidPid(0x16c0, 0x27dd))
+        .manufacturer("blue-pill")
+        .product("accel-telemetry")
+        .serial_number("0001")
+        .device_class(USB_CLASS_CDC)
+        .build();
+
+    (usb_dev, serial)
+}